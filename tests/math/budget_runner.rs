@@ -0,0 +1,187 @@
+//! Wall-clock and fuel budgets for saturating `EGraph<Math, Meta>`.
+//!
+//! `egg::SimpleRunner` (used elsewhere in this file via `use egg::*`) only exposes
+//! `with_iter_limit`/`with_node_limit`. This tree doesn't vendor the rest of `egg`'s own
+//! `src/`, so there's no way to add `with_time_limit`/`with_fuel` to that type directly;
+//! `BudgetedRunner` drives the same `search`/`apply` rewrite loop through `Rewrite`'s
+//! public API instead, for rule sets (like associativity/commutativity) where a single
+//! iteration can blow up the e-graph before the node limit is even checked again.
+
+use std::time::{Duration, Instant};
+
+use crate::{EGraph, Math, Rewrite};
+use egg::{Id, RecExpr};
+
+/// Why a [`BudgetedRunner`] stopped.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StopReason {
+    /// No rule matched in an iteration; the e-graph is saturated.
+    Saturated,
+    /// Hit the configured iteration limit.
+    IterationLimit(usize),
+    /// The e-graph grew past the configured node limit.
+    NodeLimit(usize),
+    /// Ran for longer than the configured wall-clock budget.
+    TimeLimit(Duration),
+    /// Exhausted the configured rewrite-application fuel.
+    FuelExhausted,
+}
+
+pub struct BudgetedRunner {
+    iter_limit: usize,
+    node_limit: usize,
+    time_limit: Option<Duration>,
+    fuel: Option<u64>,
+}
+
+impl Default for BudgetedRunner {
+    fn default() -> Self {
+        Self {
+            iter_limit: 30,
+            node_limit: 10_000,
+            time_limit: None,
+            fuel: None,
+        }
+    }
+}
+
+impl BudgetedRunner {
+    pub fn with_iter_limit(mut self, limit: usize) -> Self {
+        self.iter_limit = limit;
+        self
+    }
+
+    pub fn with_node_limit(mut self, limit: usize) -> Self {
+        self.node_limit = limit;
+        self
+    }
+
+    /// Checked between iterations and between rule applications within an iteration, not
+    /// on every match, so it stays cheap on small problems.
+    pub fn with_time_limit(mut self, limit: Duration) -> Self {
+        self.time_limit = Some(limit);
+        self
+    }
+
+    /// Charges one unit of fuel per successful rewrite application.
+    pub fn with_fuel(mut self, fuel: u64) -> Self {
+        self.fuel = Some(fuel);
+        self
+    }
+
+    pub fn run(&self, egraph: &mut EGraph, rules: &[Rewrite]) -> (usize, StopReason) {
+        let start = Instant::now();
+        let mut fuel = self.fuel;
+
+        if egraph.total_size() > self.node_limit {
+            return (0, StopReason::NodeLimit(self.node_limit));
+        }
+
+        for iteration in 0..self.iter_limit {
+            if egraph.total_size() > self.node_limit {
+                return (iteration, StopReason::NodeLimit(self.node_limit));
+            }
+            if let Some(limit) = self.time_limit {
+                if start.elapsed() > limit {
+                    return (iteration, StopReason::TimeLimit(limit));
+                }
+            }
+
+            let matches: Vec<_> = rules.iter().map(|rule| rule.search(egraph)).collect();
+
+            let mut applied_any = false;
+            'rules: for (rule, matches) in rules.iter().zip(matches) {
+                // When fuel is limited, apply one match at a time so a single rule that
+                // matches hundreds of e-classes can't blow past the budget in one shot.
+                let batch_size = match fuel {
+                    Some(remaining) => (remaining as usize).min(matches.len()),
+                    None => matches.len(),
+                }
+                .max(1);
+
+                for batch in matches.chunks(batch_size) {
+                    if fuel == Some(0) {
+                        return (iteration, StopReason::FuelExhausted);
+                    }
+
+                    let applied: Vec<Id> = rule.apply(egraph, batch);
+                    if !applied.is_empty() {
+                        applied_any = true;
+                    }
+
+                    if let Some(remaining) = fuel.as_mut() {
+                        *remaining = remaining.saturating_sub(applied.len() as u64);
+                    }
+
+                    if let Some(limit) = self.time_limit {
+                        if start.elapsed() > limit {
+                            return (iteration, StopReason::TimeLimit(limit));
+                        }
+                    }
+
+                    if fuel == Some(0) {
+                        return (iteration, StopReason::FuelExhausted);
+                    }
+                }
+
+                if fuel == Some(0) {
+                    break 'rules;
+                }
+            }
+
+            if !applied_any {
+                return (iteration, StopReason::Saturated);
+            }
+        }
+
+        (self.iter_limit, StopReason::IterationLimit(self.iter_limit))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules;
+
+    #[test]
+    fn fuel_exhausted_stops_a_blowup_rule_set() {
+        let start: RecExpr<Math> = "(+ 1 (+ 2 (+ 3 (+ 4 (+ 5 (+ 6 7))))))".parse().unwrap();
+        let (mut egraph, _root) = EGraph::from_expr(&start);
+
+        let (_, reason) = BudgetedRunner::default()
+            .with_iter_limit(20)
+            .with_node_limit(100_000)
+            .with_fuel(3)
+            .run(&mut egraph, &rules());
+
+        assert_eq!(reason, StopReason::FuelExhausted);
+    }
+
+    #[test]
+    fn time_limit_stops_a_blowup_rule_set() {
+        let start: RecExpr<Math> = "(+ 1 (+ 2 (+ 3 (+ 4 (+ 5 (+ 6 7))))))".parse().unwrap();
+        let (mut egraph, _root) = EGraph::from_expr(&start);
+
+        let (_, reason) = BudgetedRunner::default()
+            .with_iter_limit(20)
+            .with_node_limit(100_000)
+            .with_time_limit(Duration::from_nanos(1))
+            .run(&mut egraph, &rules());
+
+        assert_eq!(reason, StopReason::TimeLimit(Duration::from_nanos(1)));
+    }
+
+    #[test]
+    fn saturates_without_a_fuel_or_time_limit() {
+        let start: RecExpr<Math> = "(+ x 0)".parse().unwrap();
+        let (mut egraph, _root) = EGraph::from_expr(&start);
+
+        let zero_add: Rewrite = egg::rw("zero-add").p("(+ ?a 0)").a("?a").mk();
+        let (_, reason) = BudgetedRunner::default()
+            .with_iter_limit(5)
+            .with_node_limit(1_000)
+            .run(&mut egraph, &[zero_add]);
+
+        assert_eq!(reason, StopReason::Saturated);
+    }
+}
@@ -0,0 +1,288 @@
+//! A flat bytecode compiler and stack machine for evaluating `Math` expressions.
+//!
+//! `Meta::make` only ever folds constants one node at a time while the e-graph is being
+//! built. Once an `Extractor` has picked a `best` expression out of a saturated e-graph,
+//! re-walking that tree for every input assignment is wasteful. [`compile`] flattens a
+//! `RecExpr<Math>` into a [`Chunk`] once, and [`Vm`] runs that chunk against a variable
+//! environment as many times as needed.
+
+use crate::{Constant, Math};
+use egg::RecExpr;
+use ordered_float::NotNan;
+use std::fmt;
+
+/// A single bytecode instruction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Inst {
+    PushConst(Constant),
+    /// Load the variable at this index into the pre-resolved variable table.
+    LoadVar(u32),
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Pow,
+    Exp,
+    Log,
+    Sqrt,
+    Cbrt,
+    Fabs,
+    Log1p,
+    Expm1,
+}
+
+/// A compiled, flat form of a `RecExpr<Math>`, ready to be run by a [`Vm`].
+#[derive(Debug, Clone, Default)]
+pub struct Chunk {
+    insts: Vec<Inst>,
+    /// Names of the `Variable` leaves, in the order `LoadVar` indices refer to them.
+    vars: Vec<String>,
+}
+
+impl Chunk {
+    pub fn insts(&self) -> &[Inst] {
+        &self.insts
+    }
+
+    pub fn vars(&self) -> &[String] {
+        &self.vars
+    }
+}
+
+/// Compiles a `RecExpr<Math>` into a flat [`Chunk`] by walking it in post-order.
+pub fn compile(expr: &RecExpr<Math>) -> Result<Chunk, EvalError> {
+    let mut chunk = Chunk::default();
+    compile_into(expr, &mut chunk)?;
+    Ok(chunk)
+}
+
+fn compile_into(expr: &RecExpr<Math>, chunk: &mut Chunk) -> Result<(), EvalError> {
+    let node = expr.as_ref();
+    for child in &node.children {
+        compile_into(child, chunk)?;
+    }
+
+    let inst = match &node.op {
+        Math::Constant(c) => Inst::PushConst(*c),
+        Math::Variable(name) => {
+            let idx = chunk
+                .vars
+                .iter()
+                .position(|v| v == name)
+                .unwrap_or_else(|| {
+                    chunk.vars.push(name.clone());
+                    chunk.vars.len() - 1
+                });
+            Inst::LoadVar(idx as u32)
+        }
+        Math::Add => Inst::Add,
+        Math::Sub => Inst::Sub,
+        Math::Mul => Inst::Mul,
+        Math::Div => Inst::Div,
+        Math::Pow => Inst::Pow,
+        Math::Exp => Inst::Exp,
+        Math::Log => Inst::Log,
+        Math::Sqrt => Inst::Sqrt,
+        Math::Cbrt => Inst::Cbrt,
+        Math::Fabs => Inst::Fabs,
+        Math::Log1p => Inst::Log1p,
+        Math::Expm1 => Inst::Expm1,
+        Math::RealToPosit => return Err(EvalError::UnsupportedOp("real->posit".into())),
+    };
+    chunk.insts.push(inst);
+    Ok(())
+}
+
+/// Bounds on how long a [`Vm`] is allowed to run, so a malformed or pathologically deep
+/// chunk can't run unbounded.
+#[derive(Debug, Clone, Copy)]
+pub struct VmLimits {
+    pub max_steps: usize,
+    pub max_stack_depth: usize,
+}
+
+impl Default for VmLimits {
+    fn default() -> Self {
+        Self {
+            max_steps: 1_000_000,
+            max_stack_depth: 10_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EvalError {
+    UnsupportedOp(String),
+    UnboundVariable(u32),
+    StackUnderflow,
+    /// The chunk didn't leave exactly one value on the stack.
+    MalformedChunk,
+    NotANumber,
+    StepLimitExceeded,
+    StackDepthExceeded,
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EvalError::UnsupportedOp(op) => write!(f, "unsupported op: {op}"),
+            EvalError::UnboundVariable(idx) => write!(f, "no binding for variable {idx}"),
+            EvalError::StackUnderflow => write!(f, "stack underflow"),
+            EvalError::MalformedChunk => write!(f, "chunk did not reduce to a single value"),
+            EvalError::NotANumber => write!(f, "operation produced NaN"),
+            EvalError::StepLimitExceeded => write!(f, "step limit exceeded"),
+            EvalError::StackDepthExceeded => write!(f, "stack depth limit exceeded"),
+        }
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+/// A stack machine that runs a compiled [`Chunk`] against a variable environment.
+pub struct Vm<'a> {
+    chunk: &'a Chunk,
+    limits: VmLimits,
+}
+
+impl<'a> Vm<'a> {
+    pub fn new(chunk: &'a Chunk) -> Self {
+        Self {
+            chunk,
+            limits: VmLimits::default(),
+        }
+    }
+
+    pub fn with_limits(mut self, limits: VmLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Runs the chunk against `env`, indexed the same way as `self.chunk.vars()`.
+    pub fn run(&self, env: &[Constant]) -> Result<Constant, EvalError> {
+        let mut stack: Vec<Constant> = Vec::new();
+
+        for (step, inst) in self.chunk.insts.iter().enumerate() {
+            if step >= self.limits.max_steps {
+                return Err(EvalError::StepLimitExceeded);
+            }
+
+            match inst {
+                Inst::PushConst(c) => stack.push(*c),
+                Inst::LoadVar(idx) => {
+                    let v = env
+                        .get(*idx as usize)
+                        .ok_or(EvalError::UnboundVariable(*idx))?;
+                    stack.push(*v);
+                }
+                Inst::Add | Inst::Sub | Inst::Mul | Inst::Div | Inst::Pow => {
+                    let b = stack.pop().ok_or(EvalError::StackUnderflow)?;
+                    let a = stack.pop().ok_or(EvalError::StackUnderflow)?;
+                    let result = match inst {
+                        Inst::Add => a.into_inner() + b.into_inner(),
+                        Inst::Sub => a.into_inner() - b.into_inner(),
+                        Inst::Mul => a.into_inner() * b.into_inner(),
+                        Inst::Div => a.into_inner() / b.into_inner(),
+                        Inst::Pow => a.into_inner().powf(b.into_inner()),
+                        _ => unreachable!(),
+                    };
+                    stack.push(NotNan::new(result).map_err(|_| EvalError::NotANumber)?);
+                }
+                Inst::Exp | Inst::Log | Inst::Sqrt | Inst::Cbrt | Inst::Fabs | Inst::Log1p
+                | Inst::Expm1 => {
+                    let a = stack.pop().ok_or(EvalError::StackUnderflow)?.into_inner();
+                    let result = match inst {
+                        Inst::Exp => a.exp(),
+                        Inst::Log => a.ln(),
+                        Inst::Sqrt => a.sqrt(),
+                        Inst::Cbrt => a.cbrt(),
+                        Inst::Fabs => a.abs(),
+                        Inst::Log1p => a.ln_1p(),
+                        Inst::Expm1 => a.exp_m1(),
+                        _ => unreachable!(),
+                    };
+                    stack.push(NotNan::new(result).map_err(|_| EvalError::NotANumber)?);
+                }
+            }
+
+            if stack.len() > self.limits.max_stack_depth {
+                return Err(EvalError::StackDepthExceeded);
+            }
+        }
+
+        if stack.len() != 1 {
+            return Err(EvalError::MalformedChunk);
+        }
+        Ok(stack[0])
+    }
+}
+
+/// The `+ - * /` constant folding used by `Meta::make`, implemented on top of the VM so
+/// there's only one place that knows how to evaluate those ops.
+pub(crate) fn eval_op(op: Math, args: &[Constant]) -> Option<Constant> {
+    let inst = match op {
+        Math::Add => Inst::Add,
+        Math::Sub => Inst::Sub,
+        Math::Mul => Inst::Mul,
+        Math::Div => Inst::Div,
+        _ => return None,
+    };
+    let chunk = Chunk {
+        insts: vec![Inst::LoadVar(0), Inst::LoadVar(1), inst],
+        vars: vec!["a".into(), "b".into()],
+    };
+    Vm::new(&chunk).run(args).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn c(v: f64) -> Constant {
+        NotNan::new(v).unwrap()
+    }
+
+    #[test]
+    fn compiles_and_runs_constants() {
+        let expr: RecExpr<Math> = "(+ 1 (* 2 3))".parse().unwrap();
+        let chunk = compile(&expr).unwrap();
+        let result = Vm::new(&chunk).run(&[]).unwrap();
+        assert_eq!(result, c(7.0));
+    }
+
+    #[test]
+    fn resolves_variables_by_name() {
+        let expr: RecExpr<Math> = "(+ x (* x y))".parse().unwrap();
+        let chunk = compile(&expr).unwrap();
+        assert_eq!(chunk.vars(), &["x".to_string(), "y".to_string()]);
+        let result = Vm::new(&chunk).run(&[c(2.0), c(3.0)]).unwrap();
+        assert_eq!(result, c(8.0));
+    }
+
+    #[test]
+    fn unbound_variable_is_an_error() {
+        let expr: RecExpr<Math> = "x".parse().unwrap();
+        let chunk = compile(&expr).unwrap();
+        assert_eq!(Vm::new(&chunk).run(&[]), Err(EvalError::UnboundVariable(0)));
+    }
+
+    #[test]
+    fn nan_producing_division_is_an_error() {
+        let expr: RecExpr<Math> = "(log -1)".parse().unwrap();
+        let chunk = compile(&expr).unwrap();
+        assert_eq!(Vm::new(&chunk).run(&[]), Err(EvalError::NotANumber));
+    }
+
+    #[test]
+    fn step_limit_is_enforced() {
+        let expr: RecExpr<Math> = "(+ 1 1)".parse().unwrap();
+        let chunk = compile(&expr).unwrap();
+        let limits = VmLimits {
+            max_steps: 1,
+            ..VmLimits::default()
+        };
+        assert_eq!(
+            Vm::new(&chunk).with_limits(limits).run(&[]),
+            Err(EvalError::StepLimitExceeded)
+        );
+    }
+}
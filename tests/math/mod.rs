@@ -2,10 +2,18 @@ use egg::*;
 
 use ordered_float::NotNan;
 
+mod budget_runner;
+mod bytecode;
+mod snapshot;
+
+pub use budget_runner::{BudgetedRunner, StopReason};
+pub use bytecode::{compile, Chunk, EvalError, Inst, Vm, VmLimits};
+pub use snapshot::{from_bytes, from_hex, to_bytes, to_hex, SnapshotError};
+
 pub type EGraph = egg::EGraph<Math, Meta>;
 pub type Rewrite = egg::Rewrite<Math, Meta>;
 
-type Constant = NotNan<f64>;
+pub(crate) type Constant = NotNan<f64>;
 
 define_language! {
     pub enum Math {
@@ -36,14 +44,7 @@ pub struct Meta {
 }
 
 fn eval(op: Math, args: &[Constant]) -> Option<Constant> {
-    let a = |i| args.get(i).cloned();
-    match op {
-        Math::Add => Some(a(0)? + a(1)?),
-        Math::Sub => Some(a(0)? - a(1)?),
-        Math::Mul => Some(a(0)? * a(1)?),
-        Math::Div => Some(a(0)? / a(1)?),
-        _ => None,
-    }
+    bytecode::eval_op(op, args)
 }
 
 impl Metadata<Math> for Meta {
@@ -138,6 +139,30 @@ fn associate_adds() {
     assert_eq!(egraph.number_of_classes(), 127);
 }
 
+#[test]
+fn budgeted_associativity_blowup_stops_on_fuel_but_stays_usable() {
+    // This is exactly the rule set the fuel/time budgets exist for: `comm-add`/`assoc-add`
+    // (plus the rest of `rules()`) generate an exponential number of classes on a deep
+    // `+` chain, and a tight fuel budget should cut the run off well before
+    // `with_node_limit` would even notice.
+    let start: RecExpr<Math> = "(+ 1 (+ 2 (+ 3 (+ 4 (+ 5 (+ 6 7))))))".parse().unwrap();
+    let (mut egraph, root) = EGraph::from_expr(&start);
+
+    let (_, reason) = BudgetedRunner::default()
+        .with_iter_limit(20)
+        .with_node_limit(100_000)
+        .with_fuel(10)
+        .run(&mut egraph, &rules());
+
+    assert_eq!(reason, StopReason::FuelExhausted);
+
+    // The e-graph a caller gets back after hitting a budget is still a real, usable
+    // e-graph: every class the root could reach still extracts to a valid expression.
+    let (cost, best) = Extractor::new(&egraph, AstSize).find_best(root);
+    assert!(cost > 0);
+    let _ = best.to_sexp();
+}
+
 macro_rules! check {
     (
         $(#[$meta:meta])*
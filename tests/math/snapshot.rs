@@ -0,0 +1,506 @@
+//! Round-trippable snapshots of `EGraph<Math, Meta>` and `RecExpr<Math>`, so a saturated
+//! e-graph can be persisted to disk and reloaded instead of re-running `SimpleRunner`.
+//!
+//! The format is a flat byte stream: a stable `u8` tag per `Math` operator (so it's
+//! deterministic across runs regardless of `define_language!`'s internal ordering),
+//! followed by whatever payload that operator needs. An `EGraph` snapshot is reconstructed
+//! by replaying `add`/`union` calls through the normal public API, so `Metadata::make` and
+//! `Metadata::modify` run exactly as they would during a live run; the recorded `cost` and
+//! `best` of each class are then checked against the rebuilt graph as an invariant check,
+//! rather than poked into place directly.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use egg::{ENode, Extractor, Id, RecExpr};
+use ordered_float::NotNan;
+
+use crate::{AstSize, Constant, EGraph, Math, Meta};
+
+const MAGIC: &[u8; 4] = b"EGGS";
+const VERSION: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SnapshotError {
+    Truncated,
+    BadMagic,
+    UnsupportedVersion(u8),
+    UnknownTag(u8),
+    InvalidUtf8,
+    InvalidFloat,
+    OddHexLength,
+    InvalidHexDigit(char),
+    /// The reloaded graph doesn't match the cost/shape that was recorded at save time.
+    InvariantViolation(String),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Truncated => write!(f, "unexpected end of input"),
+            SnapshotError::BadMagic => write!(f, "not an egraph snapshot"),
+            SnapshotError::UnsupportedVersion(v) => write!(f, "unsupported snapshot version {v}"),
+            SnapshotError::UnknownTag(t) => write!(f, "unknown operator tag {t}"),
+            SnapshotError::InvalidUtf8 => write!(f, "variable name is not valid utf8"),
+            SnapshotError::InvalidFloat => write!(f, "constant payload is not a valid float"),
+            SnapshotError::OddHexLength => write!(f, "hex input has an odd number of digits"),
+            SnapshotError::InvalidHexDigit(c) => write!(f, "invalid hex digit: {c:?}"),
+            SnapshotError::InvariantViolation(msg) => write!(f, "corrupt snapshot: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+// Stable tags for `Math` operators. These must never be renumbered once shipped, since
+// that would silently change the meaning of existing snapshots on disk.
+const TAG_CONSTANT: u8 = 0;
+const TAG_VARIABLE: u8 = 1;
+const TAG_ADD: u8 = 2;
+const TAG_SUB: u8 = 3;
+const TAG_MUL: u8 = 4;
+const TAG_DIV: u8 = 5;
+const TAG_POW: u8 = 6;
+const TAG_EXP: u8 = 7;
+const TAG_LOG: u8 = 8;
+const TAG_SQRT: u8 = 9;
+const TAG_CBRT: u8 = 10;
+const TAG_FABS: u8 = 11;
+const TAG_LOG1P: u8 = 12;
+const TAG_EXPM1: u8 = 13;
+const TAG_REAL_TO_POSIT: u8 = 14;
+
+fn write_op(op: &Math, out: &mut Vec<u8>) {
+    match op {
+        Math::Constant(c) => {
+            out.push(TAG_CONSTANT);
+            out.extend_from_slice(&c.into_inner().to_le_bytes());
+        }
+        Math::Variable(name) => {
+            out.push(TAG_VARIABLE);
+            out.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            out.extend_from_slice(name.as_bytes());
+        }
+        Math::Add => out.push(TAG_ADD),
+        Math::Sub => out.push(TAG_SUB),
+        Math::Mul => out.push(TAG_MUL),
+        Math::Div => out.push(TAG_DIV),
+        Math::Pow => out.push(TAG_POW),
+        Math::Exp => out.push(TAG_EXP),
+        Math::Log => out.push(TAG_LOG),
+        Math::Sqrt => out.push(TAG_SQRT),
+        Math::Cbrt => out.push(TAG_CBRT),
+        Math::Fabs => out.push(TAG_FABS),
+        Math::Log1p => out.push(TAG_LOG1P),
+        Math::Expm1 => out.push(TAG_EXPM1),
+        Math::RealToPosit => out.push(TAG_REAL_TO_POSIT),
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, pos: 0 }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], SnapshotError> {
+        let end = self.pos + n;
+        let slice = self.bytes.get(self.pos..end).ok_or(SnapshotError::Truncated)?;
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, SnapshotError> {
+        let bytes: [u8; 4] = self.take(4)?.try_into().unwrap();
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64, SnapshotError> {
+        let bytes: [u8; 8] = self.take(8)?.try_into().unwrap();
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn f64(&mut self) -> Result<f64, SnapshotError> {
+        let bytes: [u8; 8] = self.take(8)?.try_into().unwrap();
+        Ok(f64::from_le_bytes(bytes))
+    }
+
+    fn op(&mut self) -> Result<Math, SnapshotError> {
+        match self.u8()? {
+            TAG_CONSTANT => {
+                let v = self.f64()?;
+                let c = NotNan::new(v).map_err(|_| SnapshotError::InvalidFloat)?;
+                Ok(Math::Constant(c))
+            }
+            TAG_VARIABLE => {
+                let len = self.u32()? as usize;
+                let bytes = self.take(len)?;
+                let name = std::str::from_utf8(bytes)
+                    .map_err(|_| SnapshotError::InvalidUtf8)?
+                    .to_owned();
+                Ok(Math::Variable(name))
+            }
+            TAG_ADD => Ok(Math::Add),
+            TAG_SUB => Ok(Math::Sub),
+            TAG_MUL => Ok(Math::Mul),
+            TAG_DIV => Ok(Math::Div),
+            TAG_POW => Ok(Math::Pow),
+            TAG_EXP => Ok(Math::Exp),
+            TAG_LOG => Ok(Math::Log),
+            TAG_SQRT => Ok(Math::Sqrt),
+            TAG_CBRT => Ok(Math::Cbrt),
+            TAG_FABS => Ok(Math::Fabs),
+            TAG_LOG1P => Ok(Math::Log1p),
+            TAG_EXPM1 => Ok(Math::Expm1),
+            TAG_REAL_TO_POSIT => Ok(Math::RealToPosit),
+            other => Err(SnapshotError::UnknownTag(other)),
+        }
+    }
+}
+
+/// Serializes a `RecExpr<Math>` in post-order: each node's children are written before the
+/// node itself, followed by the node's arity, so `from_bytes` can rebuild it with a stack.
+pub fn expr_to_bytes(expr: &RecExpr<Math>) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_expr(expr, &mut out);
+    out
+}
+
+fn write_expr(expr: &RecExpr<Math>, out: &mut Vec<u8>) {
+    let node = expr.as_ref();
+    for child in &node.children {
+        write_expr(child, out);
+    }
+    write_op(&node.op, out);
+    out.push(node.children.len() as u8);
+}
+
+pub fn expr_from_bytes(bytes: &[u8]) -> Result<RecExpr<Math>, SnapshotError> {
+    let mut reader = Reader::new(bytes);
+    let expr = read_expr(&mut reader)?;
+    Ok(expr)
+}
+
+fn read_expr(reader: &mut Reader) -> Result<RecExpr<Math>, SnapshotError> {
+    let mut stack: Vec<RecExpr<Math>> = Vec::new();
+    while reader.pos < reader.bytes.len() {
+        let op = reader.op()?;
+        let arity = reader.u8()? as usize;
+        let at = stack.len().checked_sub(arity).ok_or(SnapshotError::Truncated)?;
+        let children: Vec<_> = stack.split_off(at);
+        stack.push(ENode::new(op, children).into());
+    }
+    if stack.len() != 1 {
+        return Err(SnapshotError::InvariantViolation(
+            "expression did not reduce to a single root".into(),
+        ));
+    }
+    Ok(stack.pop().unwrap())
+}
+
+pub fn expr_to_hex(expr: &RecExpr<Math>) -> String {
+    to_hex(&expr_to_bytes(expr))
+}
+
+pub fn expr_from_hex(s: &str) -> Result<RecExpr<Math>, SnapshotError> {
+    expr_from_bytes(&from_hex(s)?)
+}
+
+/// Serializes every e-class of `egraph`: its canonical id, member nodes (with children
+/// referenced by canonical class id), and its `Meta`'s `cost`/`best`.
+pub fn to_bytes(egraph: &EGraph) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.push(VERSION);
+
+    let classes: Vec<_> = egraph.classes().collect();
+    out.extend_from_slice(&(classes.len() as u32).to_le_bytes());
+
+    for class in &classes {
+        out.extend_from_slice(&u32::from(class.id).to_le_bytes());
+
+        out.extend_from_slice(&(class.metadata.cost as u64).to_le_bytes());
+        let best = expr_to_bytes(&class.metadata.best);
+        out.extend_from_slice(&(best.len() as u32).to_le_bytes());
+        out.extend_from_slice(&best);
+
+        out.extend_from_slice(&(class.nodes.len() as u32).to_le_bytes());
+        for node in &class.nodes {
+            write_op(&node.op, &mut out);
+            out.push(node.children.len() as u8);
+            for child in &node.children {
+                out.extend_from_slice(&u32::from(egraph.find(*child)).to_le_bytes());
+            }
+        }
+    }
+
+    out
+}
+
+struct RawClass {
+    old_id: u32,
+    cost: u64,
+    best: RecExpr<Math>,
+    nodes: Vec<(Math, Vec<u32>)>,
+}
+
+/// Rebuilds an `EGraph<Math, Meta>` by replaying `add`/`union` through the public API, then
+/// checks the reloaded classes against the recorded cost/best as a corruption check.
+pub fn from_bytes(bytes: &[u8]) -> Result<EGraph, SnapshotError> {
+    let mut reader = Reader::new(bytes);
+    if reader.take(4)? != MAGIC {
+        return Err(SnapshotError::BadMagic);
+    }
+    let version = reader.u8()?;
+    if version != VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+
+    let num_classes = reader.u32()? as usize;
+    let mut remaining = Vec::with_capacity(num_classes);
+    for _ in 0..num_classes {
+        let old_id = reader.u32()?;
+        let cost = reader.u64()?;
+        let best_len = reader.u32()? as usize;
+        let best = expr_from_bytes(reader.take(best_len)?)?;
+
+        let num_nodes = reader.u32()? as usize;
+        let mut nodes = Vec::with_capacity(num_nodes);
+        for _ in 0..num_nodes {
+            let op = reader.op()?;
+            let arity = reader.u8()? as usize;
+            let mut children = Vec::with_capacity(arity);
+            for _ in 0..arity {
+                children.push(reader.u32()?);
+            }
+            nodes.push((op, children));
+        }
+        remaining.push(RawClass {
+            old_id,
+            cost,
+            best,
+            nodes,
+        });
+    }
+
+    let mut egraph = EGraph::default();
+    let mut id_map: HashMap<u32, Id> = HashMap::new();
+
+    // Phase 1: give every class a placeholder id up front, so node children can resolve
+    // regardless of add order. This matters because this language's own rewrites produce
+    // self-referential classes routinely: `rw("add-zero").p("?a").a("(+ ?a 0)")` matches
+    // every class, so a saturated class commonly contains a node whose own child is that
+    // same class, which a "children must already be known" topological walk can never
+    // make progress on.
+    //
+    // Each placeholder is wrapped in enough extra `Fabs` nodes that its cost exceeds the
+    // class's recorded cost, so `Metadata::merge`/`modify` can never mistake it for `best`
+    // and prune the real nodes away once they're unioned in during phase 2.
+    for class in &remaining {
+        let placeholder = add_placeholder(&mut egraph, class.old_id, class.cost);
+        id_map.insert(class.old_id, placeholder);
+    }
+
+    // Phase 2: every child reference now resolves to some id, so add the real nodes and
+    // fold them into the class that owns their placeholder. Children only resolve to a
+    // real id if that id was actually declared as a class in this snapshot; anything else
+    // (truncated data, fuzzed bytes, an id from a different snapshot) is corruption, not a
+    // panic.
+    for class in &remaining {
+        let mut class_id = id_map[&class.old_id];
+        for (op, children) in &class.nodes {
+            let resolved: Vec<Id> = children
+                .iter()
+                .map(|c| {
+                    id_map.get(c).copied().ok_or_else(|| {
+                        SnapshotError::InvariantViolation(format!(
+                            "node references unknown class {c}"
+                        ))
+                    })
+                })
+                .collect::<Result<_, _>>()?;
+            let new_id = egraph.add(ENode::new(op.clone(), resolved));
+            class_id = egraph.union(class_id, new_id);
+        }
+        id_map.insert(class.old_id, class_id);
+    }
+
+    // `add`/`union` only maintain the hashcons invariant for the pairs of classes they
+    // touch directly; a batch of manual unions needs an explicit `rebuild` to restore full
+    // congruence closure before anything (like the extractor below) walks the graph.
+    egraph.rebuild();
+
+    // Phase 3: only once every class in the graph has its real nodes and the graph has
+    // been rebuilt is a class's metadata guaranteed to have converged (it can depend on
+    // its children's metadata), so the cost check has to wait until after phase 2
+    // finishes for the whole graph.
+    for class in &remaining {
+        let canonical = egraph.find(id_map[&class.old_id]);
+        let actual_cost = Extractor::new(&egraph, AstSize).find_best(canonical).0 as u64;
+        if actual_cost != class.cost {
+            return Err(SnapshotError::InvariantViolation(format!(
+                "class {} expected cost {} but rebuilt to {}",
+                class.old_id, class.cost, actual_cost
+            )));
+        }
+        // `best` was parsed above for format parity with `to_bytes`; the cost check is
+        // the actual corruption check, since re-extracting `best` here could legitimately
+        // disagree with what was recorded when several expressions tie on cost.
+        let _ = &class.best;
+    }
+
+    Ok(egraph)
+}
+
+/// Adds a fresh, uniquely-named leaf wrapped in `min_cost + 1` `Fabs` nodes, so its cost
+/// (`min_cost + 2`) is guaranteed to exceed any real node recorded for the class.
+fn add_placeholder(egraph: &mut EGraph, old_id: u32, min_cost: u64) -> Id {
+    let mut id = egraph.add(ENode::leaf(Math::Variable(format!(
+        "__snapshot_placeholder_{old_id}"
+    ))));
+    for _ in 0..=min_cost {
+        id = egraph.add(ENode::new(Math::Fabs, vec![id]));
+    }
+    id
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        s.push_str(&format!("{:02x}", b));
+    }
+    s
+}
+
+pub fn from_hex(s: &str) -> Result<Vec<u8>, SnapshotError> {
+    let digits: Vec<u8> = s
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| {
+            c.to_digit(16)
+                .map(|d| d as u8)
+                .ok_or(SnapshotError::InvalidHexDigit(c))
+        })
+        .collect::<Result<_, _>>()?;
+    if digits.len() % 2 != 0 {
+        return Err(SnapshotError::OddHexLength);
+    }
+    Ok(digits.chunks(2).map(|pair| (pair[0] << 4) | pair[1]).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expr_roundtrips_through_bytes() {
+        let expr: RecExpr<Math> = "(+ 1 (* x 2))".parse().unwrap();
+        let bytes = expr_to_bytes(&expr);
+        let back = expr_from_bytes(&bytes).unwrap();
+        assert_eq!(expr.to_sexp(), back.to_sexp());
+    }
+
+    #[test]
+    fn expr_roundtrips_through_hex() {
+        let expr: RecExpr<Math> = "(sqrt (+ x y))".parse().unwrap();
+        let hex = expr_to_hex(&expr);
+        assert!(hex.chars().all(|c| c.is_ascii_hexdigit() && !c.is_ascii_uppercase()));
+        let back = expr_from_hex(&hex).unwrap();
+        assert_eq!(expr.to_sexp(), back.to_sexp());
+    }
+
+    #[test]
+    fn from_hex_ignores_whitespace_and_case() {
+        let bytes = from_hex("DE AD\nbe ef").unwrap();
+        assert_eq!(bytes, vec![0xde, 0xad, 0xbe, 0xef]);
+    }
+
+    #[test]
+    fn egraph_roundtrips_through_bytes() {
+        let start: RecExpr<Math> = "(+ 1 (* x 2))".parse().unwrap();
+        let (egraph, _root) = EGraph::from_expr(&start);
+
+        let bytes = to_bytes(&egraph);
+        let reloaded = from_bytes(&bytes).unwrap();
+        assert_eq!(reloaded.number_of_classes(), egraph.number_of_classes());
+    }
+
+    #[test]
+    fn egraph_with_self_referential_classes_roundtrips() {
+        // `add-zero`/`mul-one` match every class, so saturating with the real rule set
+        // routinely produces a class containing a node whose own child is that same
+        // class. This is the shape `from_bytes` has to be able to rebuild.
+        let start: RecExpr<Math> = "(+ x (* x y))".parse().unwrap();
+        let (mut egraph, _root) = EGraph::from_expr(&start);
+        egg::SimpleRunner::default()
+            .with_iter_limit(5)
+            .with_node_limit(1_000)
+            .run(&mut egraph, &crate::rules());
+
+        let bytes = to_bytes(&egraph);
+        let reloaded = from_bytes(&bytes).unwrap();
+        assert_eq!(reloaded.number_of_classes(), egraph.number_of_classes());
+    }
+
+    #[test]
+    fn egraph_with_many_interdependent_classes_roundtrips() {
+        // Unlike the two-class self-reference case above, distributing/factoring a
+        // product of sums produces several classes whose metadata depends on each
+        // other's, so `from_bytes`'s cost check only sees correct numbers if it
+        // `rebuild()`s the graph before extracting, same as any other caller that
+        // drives a batch of manual unions.
+        let start: RecExpr<Math> = "(* (+ a b) (+ c d))".parse().unwrap();
+        let (mut egraph, _root) = EGraph::from_expr(&start);
+        egg::SimpleRunner::default()
+            .with_iter_limit(6)
+            .with_node_limit(2_000)
+            .run(&mut egraph, &crate::rules());
+        assert!(egraph.number_of_classes() >= 3);
+
+        let bytes = to_bytes(&egraph);
+        let reloaded = from_bytes(&bytes).unwrap();
+        assert_eq!(reloaded.number_of_classes(), egraph.number_of_classes());
+    }
+
+    #[test]
+    fn from_bytes_rejects_dangling_class_references_without_panicking() {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.extend_from_slice(&1u32.to_le_bytes()); // one class
+
+        bytes.extend_from_slice(&0u32.to_le_bytes()); // old_id = 0
+        bytes.extend_from_slice(&1u64.to_le_bytes()); // cost
+
+        let leaf: RecExpr<Math> = "a".parse().unwrap();
+        let best = expr_to_bytes(&leaf);
+        bytes.extend_from_slice(&(best.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&best);
+
+        bytes.extend_from_slice(&1u32.to_le_bytes()); // one node
+        write_op(&Math::Add, &mut bytes);
+        bytes.push(2); // arity
+        bytes.extend_from_slice(&0u32.to_le_bytes()); // child: this class itself
+        bytes.extend_from_slice(&99u32.to_le_bytes()); // child: never declared
+
+        assert_eq!(
+            from_bytes(&bytes),
+            Err(SnapshotError::InvariantViolation(
+                "node references unknown class 99".into()
+            ))
+        );
+    }
+
+    #[test]
+    fn from_bytes_rejects_bad_magic() {
+        assert_eq!(from_bytes(&[0, 0, 0, 0]), Err(SnapshotError::BadMagic));
+    }
+}